@@ -9,13 +9,49 @@ use ruma::{
         room::{guest_access, history_visibility, join_rules, member, name, topic},
         EventType,
     },
-    Raw, RoomAliasId, RoomId, RoomVersionId,
+    Raw, RoomAliasId, RoomId, RoomVersionId, ServerName,
 };
 use std::{cmp::max, collections::BTreeMap, convert::TryFrom};
 
 #[cfg(feature = "conduit_bin")]
 use rocket::{get, post};
 
+/// Maximum length of any Matrix identifier (room alias, room id, event id, ...), in bytes.
+const MAX_IDENTIFIER_LEN: usize = 255;
+
+/// Validates a room alias localpart against the Matrix grammar — printable ASCII
+/// only (`0x21`-`0x7E`), excluding `:` and `#` — and checks that the resulting
+/// `#localpart:server_name` fits within the 255-byte identifier length limit, ahead
+/// of building the full `RoomAliasId`. Called by `create_room_route`.
+///
+/// This is an allow-list rather than a deny-list of a few Unicode categories: any
+/// non-ASCII codepoint (emoji, combining marks, bidi/RTL overrides, zero-width
+/// characters that Unicode doesn't classify as whitespace or control) is rejected,
+/// since a federated identifier has no business carrying characters that can be
+/// used to spoof or visually confuse another alias.
+pub(crate) fn validate_alias_localpart(localpart: &str, server_name: &ServerName) -> Result<(), Error> {
+    if localpart.is_empty()
+        || !localpart
+            .bytes()
+            .all(|b| (0x21..=0x7E).contains(&b) && b != b':' && b != b'#')
+    {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room alias contains invalid characters.",
+        ));
+    }
+
+    // "#" + localpart + ":" + server_name must fit in the 255-byte identifier bound.
+    if 1 + localpart.len() + 1 + server_name.as_str().len() > MAX_IDENTIFIER_LEN {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Room alias is too long.",
+        ));
+    }
+
+    Ok(())
+}
+
 #[cfg_attr(
     feature = "conduit_bin",
     post("/_matrix/client/r0/createRoom", data = "<body>")
@@ -32,7 +68,8 @@ pub fn create_room_route(
         .room_alias_name
         .as_ref()
         .map_or(Ok(None), |localpart| {
-            // TODO: Check for invalid characters and maximum length
+            validate_alias_localpart(localpart, db.globals.server_name())?;
+
             let alias =
                 RoomAliasId::try_from(format!("#{}:{}", localpart, db.globals.server_name()))
                     .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid alias."))?;
@@ -47,213 +84,134 @@ pub fn create_room_route(
             }
         })?;
 
-    let mut content = ruma::events::room::create::CreateEventContent::new(sender_id.clone());
-    content.federate = body.creation_content.as_ref().map_or(true, |c| c.federate);
-    content.predecessor = body
-        .creation_content
-        .as_ref()
-        .and_then(|c| c.predecessor.clone());
-    content.room_version = RoomVersionId::Version6;
+    // Validate every piece of client-supplied content up front, before appending a
+    // single PDU, so a malformed request fails cleanly with nothing persisted.
+    let power_levels_content = build_power_levels_content(&body, sender_id)?;
 
-    // 1. The room create event
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomCreate,
-            content: serde_json::to_value(content).expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
-
-    // 2. Let the room creator join
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomMember,
-            content: serde_json::to_value(member::MemberEventContent {
-                membership: member::MembershipState::Join,
-                displayname: db.users.displayname(&sender_id)?,
-                avatar_url: db.users.avatar_url(&sender_id)?,
-                is_direct: body.is_direct,
-                third_party_invite: None,
-            })
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some(sender_id.to_string()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
-
-    // 3. Power levels
-    let mut users = BTreeMap::new();
-    users.insert(sender_id.clone(), 100.into());
-    for invite_ in &body.invite {
-        users.insert(invite_.clone(), 100.into());
-    }
-
-    let power_levels_content = if let Some(power_levels) = &body.power_level_content_override {
-        serde_json::from_str(power_levels.json().get()).map_err(|_| {
-            Error::BadRequest(ErrorKind::BadJson, "Invalid power_level_content_override.")
-        })?
-    } else {
-        serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
-            ban: 50.into(),
-            events: BTreeMap::new(),
-            events_default: 0.into(),
-            invite: 50.into(),
-            kick: 50.into(),
-            redact: 50.into(),
-            state_default: 50.into(),
-            users,
-            users_default: 0.into(),
-            notifications: ruma::events::room::power_levels::NotificationPowerLevels {
-                room: 50.into(),
-            },
+    let initial_state_events = body
+        .initial_state
+        .iter()
+        .filter(|event| {
+            // Silently skip encryption events if they are not allowed
+            !(event.event_type == EventType::RoomEncryption && db.globals.encryption_disabled())
         })
-        .expect("event is valid, we just created it")
-    };
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomPowerLevels,
-            content: power_levels_content,
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
+        .map(|event| {
+            let content = serde_json::from_str(event.content.get()).map_err(|_| {
+                Error::BadRequest(ErrorKind::BadJson, "Invalid initial_state content.")
+            })?;
+            Ok((event.event_type.clone(), event.state_key.clone(), content))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
-    // 4. Events set by preset
+    let name_content = body
+        .name
+        .as_ref()
+        .map(|name| {
+            name::NameEventContent::new(name.clone())
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Name is invalid."))
+        })
+        .transpose()?;
 
-    // Figure out preset. We need it for preset specific events
-    let visibility = body.visibility.unwrap_or(room::Visibility::Private);
-    let preset = body.preset.unwrap_or_else(|| match visibility {
-        room::Visibility::Private => create_room::RoomPreset::PrivateChat,
-        room::Visibility::Public => create_room::RoomPreset::PublicChat,
-    });
+    // Reserve the alias now that the rest of the request has been validated; it is
+    // released again below if room creation fails partway through.
+    if let Some(alias) = &alias {
+        db.rooms.set_alias(alias, Some(&room_id), &db.globals)?;
+    }
 
-    // 4.1 Join Rules
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomJoinRules,
-            content: match preset {
-                create_room::RoomPreset::PublicChat => serde_json::to_value(
-                    join_rules::JoinRulesEventContent::new(join_rules::JoinRule::Public),
-                )
-                .expect("event is valid, we just created it"),
-                // according to spec "invite" is the default
-                _ => serde_json::to_value(join_rules::JoinRulesEventContent::new(
-                    join_rules::JoinRule::Invite,
-                ))
-                .expect("event is valid, we just created it"),
+    let result = (|| -> Result<(), Error> {
+        let mut content = ruma::events::room::create::CreateEventContent::new(sender_id.clone());
+        content.federate = body.creation_content.as_ref().map_or(true, |c| c.federate);
+        content.predecessor = body
+            .creation_content
+            .as_ref()
+            .and_then(|c| c.predecessor.clone());
+        content.room_version = RoomVersionId::Version6;
+        // Propagate `type: "m.space"` (and any other room type) so the create event
+        // flags the room as a space, enabling m.space.child/m.space.parent hierarchies.
+        content.room_type = body
+            .creation_content
+            .as_ref()
+            .and_then(|c| c.room_type.clone());
+
+        // 1. The room create event
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: room_id.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomCreate,
+                content: serde_json::to_value(content)
+                    .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some("".to_owned()),
+                redacts: None,
             },
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
-
-    // 4.2 History Visibility
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomHistoryVisibility,
-            content: serde_json::to_value(history_visibility::HistoryVisibilityEventContent::new(
-                history_visibility::HistoryVisibility::Shared,
-            ))
-            .expect("event is valid, we just created it"),
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
+            &db.globals,
+            &db.account_data,
+        )?;
 
-    // 4.3 Guest Access
-    db.rooms.append_pdu(
-        PduBuilder {
-            room_id: room_id.clone(),
-            sender: sender_id.clone(),
-            event_type: EventType::RoomGuestAccess,
-            content: match preset {
-                create_room::RoomPreset::PublicChat => {
-                    serde_json::to_value(guest_access::GuestAccessEventContent::new(
-                        guest_access::GuestAccess::Forbidden,
-                    ))
-                    .expect("event is valid, we just created it")
-                }
-                _ => serde_json::to_value(guest_access::GuestAccessEventContent::new(
-                    guest_access::GuestAccess::CanJoin,
-                ))
+        // 2. Let the room creator join
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: room_id.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomMember,
+                content: serde_json::to_value(member::MemberEventContent {
+                    membership: member::MembershipState::Join,
+                    displayname: db.users.displayname(&sender_id)?,
+                    avatar_url: db.users.avatar_url(&sender_id)?,
+                    is_direct: body.is_direct,
+                    third_party_invite: None,
+                })
                 .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(sender_id.to_string()),
+                redacts: None,
             },
-            unsigned: None,
-            state_key: Some("".to_owned()),
-            redacts: None,
-        },
-        &db.globals,
-        &db.account_data,
-    )?;
-
-    // 5. Events listed in initial_state
-    for create_room::InitialStateEvent {
-        event_type,
-        state_key,
-        content,
-    } in &body.initial_state
-    {
-        // Silently skip encryption events if they are not allowed
-        if event_type == &EventType::RoomEncryption && db.globals.encryption_disabled() {
-            continue;
-        }
+            &db.globals,
+            &db.account_data,
+        )?;
 
+        // 3. Power levels
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
-                event_type: event_type.clone(),
-                content: serde_json::from_str(content.get()).map_err(|_| {
-                    Error::BadRequest(ErrorKind::BadJson, "Invalid initial_state content.")
-                })?,
+                event_type: EventType::RoomPowerLevels,
+                content: power_levels_content,
                 unsigned: None,
-                state_key: state_key.clone(),
+                state_key: Some("".to_owned()),
                 redacts: None,
             },
             &db.globals,
             &db.account_data,
         )?;
-    }
 
-    // 6. Events implied by name and topic
-    if let Some(name) = &body.name {
+        // 4. Events set by preset
+
+        // Figure out preset. We need it for preset specific events
+        let visibility = body.visibility.unwrap_or(room::Visibility::Private);
+        let preset = body.preset.unwrap_or_else(|| match visibility {
+            room::Visibility::Private => create_room::RoomPreset::PrivateChat,
+            room::Visibility::Public => create_room::RoomPreset::PublicChat,
+        });
+
+        // 4.1 Join Rules
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
-                event_type: EventType::RoomName,
-                content: serde_json::to_value(
-                    name::NameEventContent::new(name.clone()).map_err(|_| {
-                        Error::BadRequest(ErrorKind::InvalidParam, "Name is invalid.")
-                    })?,
-                )
-                .expect("event is valid, we just created it"),
+                event_type: EventType::RoomJoinRules,
+                content: match preset {
+                    create_room::RoomPreset::PublicChat => serde_json::to_value(
+                        join_rules::JoinRulesEventContent::new(join_rules::JoinRule::Public),
+                    )
+                    .expect("event is valid, we just created it"),
+                    // according to spec "invite" is the default
+                    _ => serde_json::to_value(join_rules::JoinRulesEventContent::new(
+                        join_rules::JoinRule::Invite,
+                    ))
+                    .expect("event is valid, we just created it"),
+                },
                 unsigned: None,
                 state_key: Some("".to_owned()),
                 redacts: None,
@@ -261,17 +219,18 @@ pub fn create_room_route(
             &db.globals,
             &db.account_data,
         )?;
-    }
 
-    if let Some(topic) = &body.topic {
+        // 4.2 History Visibility
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
-                event_type: EventType::RoomTopic,
-                content: serde_json::to_value(topic::TopicEventContent {
-                    topic: topic.clone(),
-                })
+                event_type: EventType::RoomHistoryVisibility,
+                content: serde_json::to_value(
+                    history_visibility::HistoryVisibilityEventContent::new(
+                        history_visibility::HistoryVisibility::Shared,
+                    ),
+                )
                 .expect("event is valid, we just created it"),
                 unsigned: None,
                 state_key: Some("".to_owned()),
@@ -280,42 +239,241 @@ pub fn create_room_route(
             &db.globals,
             &db.account_data,
         )?;
-    }
 
-    // 7. Events implied by invite (and TODO: invite_3pid)
-    for user in &body.invite {
+        // 4.3 Guest Access
         db.rooms.append_pdu(
             PduBuilder {
                 room_id: room_id.clone(),
                 sender: sender_id.clone(),
-                event_type: EventType::RoomMember,
-                content: serde_json::to_value(member::MemberEventContent {
-                    membership: member::MembershipState::Invite,
-                    displayname: db.users.displayname(&user)?,
-                    avatar_url: db.users.avatar_url(&user)?,
-                    is_direct: body.is_direct,
-                    third_party_invite: None,
-                })
-                .expect("event is valid, we just created it"),
+                event_type: EventType::RoomGuestAccess,
+                content: match preset {
+                    create_room::RoomPreset::PublicChat => {
+                        serde_json::to_value(guest_access::GuestAccessEventContent::new(
+                            guest_access::GuestAccess::Forbidden,
+                        ))
+                        .expect("event is valid, we just created it")
+                    }
+                    _ => serde_json::to_value(guest_access::GuestAccessEventContent::new(
+                        guest_access::GuestAccess::CanJoin,
+                    ))
+                    .expect("event is valid, we just created it"),
+                },
                 unsigned: None,
-                state_key: Some(user.to_string()),
+                state_key: Some("".to_owned()),
                 redacts: None,
             },
             &db.globals,
             &db.account_data,
         )?;
+
+        // 5. Events listed in initial_state
+        for (event_type, state_key, content) in initial_state_events {
+            db.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: sender_id.clone(),
+                    event_type,
+                    content,
+                    unsigned: None,
+                    state_key,
+                    redacts: None,
+                },
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+
+        // 6. Events implied by name and topic
+        if let Some(name_content) = name_content {
+            db.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: sender_id.clone(),
+                    event_type: EventType::RoomName,
+                    content: serde_json::to_value(name_content)
+                        .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+
+        if let Some(topic) = &body.topic {
+            db.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: sender_id.clone(),
+                    event_type: EventType::RoomTopic,
+                    content: serde_json::to_value(topic::TopicEventContent {
+                        topic: topic.clone(),
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some("".to_owned()),
+                    redacts: None,
+                },
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+
+        // 7. Events implied by invite (and TODO: invite_3pid)
+        for user in &body.invite {
+            db.rooms.append_pdu(
+                PduBuilder {
+                    room_id: room_id.clone(),
+                    sender: sender_id.clone(),
+                    event_type: EventType::RoomMember,
+                    content: serde_json::to_value(member::MemberEventContent {
+                        membership: member::MembershipState::Invite,
+                        displayname: db.users.displayname(&user)?,
+                        avatar_url: db.users.avatar_url(&user)?,
+                        is_direct: body.is_direct,
+                        third_party_invite: None,
+                    })
+                    .expect("event is valid, we just created it"),
+                    unsigned: None,
+                    state_key: Some(user.to_string()),
+                    redacts: None,
+                },
+                &db.globals,
+                &db.account_data,
+            )?;
+        }
+
+        if let Some(room::Visibility::Public) = body.visibility {
+            db.rooms.set_public(&room_id, true)?;
+        }
+
+        Ok(())
+    })();
+
+    if let Err(error) = result {
+        // Compensating cleanup: the room was only half-built, so tear it down again
+        // rather than leaving an orphaned room id, state, and alias reservation behind.
+        // Log rather than swallow failures here: if the cleanup itself fails, the
+        // orphaned room/state/alias this path exists to avoid is left behind after all.
+        if let Some(alias) = &alias {
+            if let Err(cleanup_error) = db.rooms.set_alias(alias, None, &db.globals) {
+                log::error!(
+                    "Failed to release alias {} after aborted room creation of {}: {}",
+                    alias, room_id, cleanup_error
+                );
+            }
+        }
+        if let Err(cleanup_error) = db.rooms.delete_room(&room_id) {
+            log::error!(
+                "Failed to roll back partially created room {}: {}",
+                room_id, cleanup_error
+            );
+        }
+        return Err(error);
     }
 
-    // Homeserver specific stuff
-    if let Some(alias) = alias {
-        db.rooms.set_alias(&alias, Some(&room_id), &db.globals)?;
+    Ok(create_room::Response { room_id }.into())
+}
+
+/// Builds the `m.room.power_levels` content for a new room: just the creator at
+/// level 100 by default, or the client's `power_level_content_override` after
+/// checking it doesn't lock the creator out of the room they are about to create.
+fn build_power_levels_content(
+    body: &create_room::Request,
+    sender_id: &ruma::UserId,
+) -> Result<serde_json::Value, Error> {
+    if let Some(power_levels) = &body.power_level_content_override {
+        let content: ruma::events::room::power_levels::PowerLevelsEventContent =
+            serde_json::from_str(power_levels.json().get()).map_err(|_| {
+                Error::BadRequest(ErrorKind::BadJson, "Invalid power_level_content_override.")
+            })?;
+
+        // The creator must be able to send state events and, in particular, to send
+        // another m.room.power_levels event later.
+        let creator_level = content
+            .users
+            .get(sender_id)
+            .copied()
+            .unwrap_or(content.users_default);
+        let power_levels_level = content
+            .events
+            .get(&EventType::RoomPowerLevels)
+            .copied()
+            .unwrap_or(content.state_default);
+
+        if creator_level < content.state_default || creator_level < power_levels_level {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "power_level_content_override would lock the room creator out of their own room.",
+            ));
+        }
+
+        Ok(serde_json::to_value(content).expect("event is valid, we just created it"))
+    } else {
+        let mut users = BTreeMap::new();
+        users.insert(sender_id.clone(), 100.into());
+
+        Ok(
+            serde_json::to_value(ruma::events::room::power_levels::PowerLevelsEventContent {
+                ban: 50.into(),
+                events: BTreeMap::new(),
+                events_default: 0.into(),
+                invite: 50.into(),
+                kick: 50.into(),
+                redact: 50.into(),
+                state_default: 50.into(),
+                users,
+                users_default: 0.into(),
+                notifications: ruma::events::room::power_levels::NotificationPowerLevels {
+                    room: 50.into(),
+                },
+            })
+            .expect("event is valid, we just created it"),
+        )
     }
+}
 
-    if let Some(room::Visibility::Public) = body.visibility {
-        db.rooms.set_public(&room_id, true)?;
+/// Checks whether `sender_id` is allowed to see `event_id` in `room_id`, based on the
+/// room's `m.room.history_visibility` and the sender's membership: as it stood when
+/// the event was sent for `joined` and `invited`, at any point for `shared`. Used by
+/// `get_room_event_route` (`/event`).
+fn user_can_see_event(
+    db: &Database<'_>,
+    sender_id: &ruma::UserId,
+    room_id: &RoomId,
+    event_id: &ruma::EventId,
+) -> Result<bool, Error> {
+    let visibility = db
+        .rooms
+        .history_visibility_at_event(room_id, event_id)?
+        .unwrap_or(history_visibility::HistoryVisibility::Shared);
+
+    if visibility == history_visibility::HistoryVisibility::WorldReadable {
+        return Ok(true);
     }
 
-    Ok(create_room::Response { room_id }.into())
+    let membership_at_event = db
+        .rooms
+        .membership_at_event(sender_id, room_id, event_id)?
+        .unwrap_or(member::MembershipState::Leave);
+
+    Ok(match visibility {
+        // A pure membership-window check: whoever was joined when the event was
+        // sent may see it, even if they have since left the room.
+        history_visibility::HistoryVisibility::Joined => {
+            membership_at_event == member::MembershipState::Join
+        }
+        history_visibility::HistoryVisibility::Invited => matches!(
+            membership_at_event,
+            member::MembershipState::Join | member::MembershipState::Invite
+        ),
+        // Shared (and any legacy/unknown value) grants access to anyone who has
+        // ever actually joined the room, regardless of whether that happened
+        // before or after the event — an invite that was never accepted, or a
+        // ban, does not qualify.
+        _ => db.rooms.was_ever_joined(sender_id, room_id)?,
+    })
 }
 
 #[cfg_attr(
@@ -328,7 +486,7 @@ pub fn get_room_event_route(
 ) -> ConduitResult<get_room_event::Response> {
     let sender_id = body.sender_id.as_ref().expect("user is authenticated");
 
-    if !db.rooms.is_joined(sender_id, &body.room_id)? {
+    if !user_can_see_event(&db, sender_id, &body.room_id, &body.event_id)? {
         return Err(Error::BadRequest(
             ErrorKind::Forbidden,
             "You don't have permission to view this room.",
@@ -455,6 +613,56 @@ pub fn upgrade_room_route(
         &db.account_data,
     )?;
 
+    // Migrate the old room's members: invite everyone who was joined or invited so
+    // clients auto-follow the tombstone, and carry bans over so they aren't lost.
+    for (state_key, member_pdu) in db
+        .rooms
+        .room_state_full(&body.room_id, &EventType::RoomMember)?
+    {
+        let user_id = ruma::UserId::try_from(state_key.as_str())
+            .map_err(|_| Error::bad_database("Invalid user id in m.room.member state_key."))?;
+
+        // The upgrader already joined the new room above.
+        if &user_id == sender_id {
+            continue;
+        }
+
+        let old_membership = serde_json::from_value::<member::MemberEventContent>(
+            member_pdu.content.clone(),
+        )
+        .map_err(|_| Error::bad_database("Invalid m.room.member event in database."))?
+        .membership;
+
+        let new_membership = match old_membership {
+            member::MembershipState::Join | member::MembershipState::Invite => {
+                member::MembershipState::Invite
+            }
+            member::MembershipState::Ban => member::MembershipState::Ban,
+            _ => continue,
+        };
+
+        db.rooms.append_pdu(
+            PduBuilder {
+                room_id: replacement_room.clone(),
+                sender: sender_id.clone(),
+                event_type: EventType::RoomMember,
+                content: serde_json::to_value(member::MemberEventContent {
+                    membership: new_membership,
+                    displayname: db.users.displayname(&user_id)?,
+                    avatar_url: db.users.avatar_url(&user_id)?,
+                    is_direct: None,
+                    third_party_invite: None,
+                })
+                .expect("event is valid, we just created it"),
+                unsigned: None,
+                state_key: Some(user_id.to_string()),
+                redacts: None,
+            },
+            &db.globals,
+            &db.account_data,
+        )?;
+    }
+
     // Recommended transferable state events list from the specs
     let transferable_state_events = vec![
         EventType::RoomServerAcl,
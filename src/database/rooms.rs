@@ -0,0 +1,585 @@
+use crate::{
+    database::{account_data::AccountData, globals::Globals},
+    pdu::{PduBuilder, PduEvent},
+    utils, Error,
+};
+use ruma::{
+    events::{
+        room::{
+            history_visibility::{HistoryVisibility, HistoryVisibilityEventContent},
+            member::{MemberEventContent, MembershipState},
+        },
+        EventType,
+    },
+    EventId, RoomAliasId, RoomId, UserId,
+};
+use std::{collections::BTreeMap, convert::TryFrom, convert::TryInto};
+
+/// Position of a PDU within the server's global, monotonically increasing event
+/// count. Stored big-endian so sled's lexicographic key order matches numeric order.
+type PduCount = u64;
+
+const COUNTER: &[u8] = b"counter";
+
+pub struct Rooms {
+    /// `room_id + 0xff + count` -> PDU (every event ever appended to the room, in order)
+    pub(super) pduid_pdu: sled::Tree,
+    /// `event_id` -> `room_id + 0xff + count` (reverse lookup used by get_pdu)
+    pub(super) eventid_pduid: sled::Tree,
+    /// `room_id + 0xff + event_type + 0xff + state_key` -> PDU (current state only)
+    pub(super) roomstateid_pdu: sled::Tree,
+    /// `room_id + 0xff + user_id` -> `()` (users currently joined to a room)
+    pub(super) roomuserid_joined: sled::Tree,
+    /// `alias` -> `room_id`
+    pub(super) alias_roomid: sled::Tree,
+    /// `room_id` -> `()` (rooms that are published to the room directory)
+    pub(super) publicroomids: sled::Tree,
+    /// Single-key tree holding the global PDU counter.
+    pub(super) globalcount: sled::Tree,
+}
+
+impl Rooms {
+    fn next_count(&self) -> Result<PduCount, Error> {
+        Ok(self
+            .globalcount
+            .update_and_fetch(COUNTER, |old| {
+                let count = old
+                    .map(|bytes| {
+                        PduCount::from_be_bytes(bytes.try_into().expect("counter is valid u64"))
+                    })
+                    .unwrap_or(0)
+                    + 1;
+                Some(count.to_be_bytes().to_vec())
+            })
+            .map_err(|_| Error::bad_database("Failed to increment PDU counter."))?
+            .map(|bytes| PduCount::from_be_bytes(bytes.as_ref().try_into().expect("counter is valid u64")))
+            .unwrap_or(1))
+    }
+
+    fn pdu_id(room_id: &RoomId, count: PduCount) -> Vec<u8> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(&count.to_be_bytes());
+        key
+    }
+
+    fn state_key(room_id: &RoomId, event_type: &EventType, state_key: &str) -> Vec<u8> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(event_type.as_ref().as_bytes());
+        key.push(0xff);
+        key.extend_from_slice(state_key.as_bytes());
+        key
+    }
+
+    fn joined_key(room_id: &RoomId, user_id: &UserId) -> Vec<u8> {
+        let mut key = room_id.as_bytes().to_vec();
+        key.push(0xff);
+        key.extend_from_slice(user_id.as_bytes());
+        key
+    }
+
+    pub fn append_pdu(
+        &self,
+        pdu_builder: PduBuilder,
+        globals: &Globals,
+        _account_data: &AccountData,
+    ) -> Result<EventId, Error> {
+        let PduBuilder {
+            room_id,
+            sender,
+            event_type,
+            content,
+            unsigned,
+            state_key,
+            redacts,
+        } = pdu_builder;
+
+        let count = self.next_count()?;
+        let event_id = EventId::new(globals.server_name());
+
+        let pdu = PduEvent {
+            event_id: event_id.clone(),
+            room_id: room_id.clone(),
+            sender,
+            origin_server_ts: utils::millis_since_unix_epoch(),
+            event_type: event_type.clone(),
+            content,
+            state_key: state_key.clone(),
+            unsigned,
+            redacts,
+        };
+        let pdu_bytes = serde_json::to_vec(&pdu).expect("PduEvent can always be serialized");
+
+        let pdu_id = Self::pdu_id(&room_id, count);
+        self.pduid_pdu.insert(&pdu_id, pdu_bytes.as_slice())?;
+        self.eventid_pduid.insert(event_id.as_bytes(), pdu_id)?;
+
+        if let Some(state_key) = &state_key {
+            self.roomstateid_pdu.insert(
+                Self::state_key(&room_id, &event_type, state_key),
+                pdu_bytes.as_slice(),
+            )?;
+
+            if event_type == EventType::RoomMember {
+                let user_id = UserId::try_from(state_key.as_str())
+                    .map_err(|_| Error::bad_database("Invalid user id in m.room.member state_key."))?;
+                let membership = serde_json::from_value::<MemberEventContent>(pdu.content.clone())
+                    .map_err(|_| Error::bad_database("Invalid m.room.member event content."))?
+                    .membership;
+
+                let joined_key = Self::joined_key(&room_id, &user_id);
+                if membership == MembershipState::Join {
+                    self.roomuserid_joined.insert(joined_key, &[])?;
+                } else {
+                    self.roomuserid_joined.remove(joined_key)?;
+                }
+            }
+        }
+
+        Ok(event_id)
+    }
+
+    pub fn get_pdu(&self, event_id: &EventId) -> Result<Option<PduEvent>, Error> {
+        let pdu_id = match self.eventid_pduid.get(event_id.as_bytes())? {
+            Some(pdu_id) => pdu_id,
+            None => return Ok(None),
+        };
+
+        self.pduid_pdu
+            .get(pdu_id)?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid PDU in database."))
+            })
+            .transpose()
+    }
+
+    pub fn room_state_get(
+        &self,
+        room_id: &RoomId,
+        event_type: &EventType,
+        state_key: &str,
+    ) -> Result<Option<PduEvent>, Error> {
+        self.roomstateid_pdu
+            .get(Self::state_key(room_id, event_type, state_key))?
+            .map(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|_| Error::bad_database("Invalid PDU in database."))
+            })
+            .transpose()
+    }
+
+    /// Returns every current state event of `event_type` in `room_id`, keyed by
+    /// state_key. Used to walk the full set of `m.room.member` events when
+    /// migrating a room during an upgrade, instead of a fixed list of event types.
+    pub fn room_state_full(
+        &self,
+        room_id: &RoomId,
+        event_type: &EventType,
+    ) -> Result<BTreeMap<String, PduEvent>, Error> {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+        prefix.extend_from_slice(event_type.as_ref().as_bytes());
+        prefix.push(0xff);
+
+        self.roomstateid_pdu
+            .scan_prefix(&prefix)
+            .map(|entry| {
+                let (key, value) = entry?;
+                let state_key = String::from_utf8(key[prefix.len()..].to_vec())
+                    .map_err(|_| Error::bad_database("Invalid state_key in database."))?;
+                let pdu = serde_json::from_slice(&value)
+                    .map_err(|_| Error::bad_database("Invalid PDU in database."))?;
+                Ok((state_key, pdu))
+            })
+            .collect()
+    }
+
+    pub fn is_joined(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool, Error> {
+        Ok(self
+            .roomuserid_joined
+            .contains_key(Self::joined_key(room_id, user_id))?)
+    }
+
+    pub fn id_from_alias(&self, alias: &RoomAliasId) -> Result<Option<RoomId>, Error> {
+        self.alias_roomid
+            .get(alias.as_bytes())?
+            .map(|bytes| {
+                RoomId::try_from(utils::string_from_bytes(&bytes)?)
+                    .map_err(|_| Error::bad_database("Invalid room id in database."))
+            })
+            .transpose()
+    }
+
+    pub fn set_alias(
+        &self,
+        alias: &RoomAliasId,
+        room_id: Option<&RoomId>,
+        _globals: &Globals,
+    ) -> Result<(), Error> {
+        match room_id {
+            Some(room_id) => {
+                self.alias_roomid
+                    .insert(alias.as_bytes(), room_id.as_bytes())?;
+            }
+            None => {
+                self.alias_roomid.remove(alias.as_bytes())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn room_aliases(
+        &self,
+        room_id: &RoomId,
+    ) -> impl Iterator<Item = Result<RoomAliasId, Error>> {
+        let room_id_bytes = room_id.as_bytes().to_vec();
+        self.alias_roomid
+            .iter()
+            .filter_map(move |entry| {
+                let (key, value) = entry.ok()?;
+                if value.as_ref() != room_id_bytes.as_slice() {
+                    return None;
+                }
+                Some(
+                    RoomAliasId::try_from(utils::string_from_bytes(&key).ok()?)
+                        .map_err(|_| Error::bad_database("Invalid alias in database.")),
+                )
+            })
+    }
+
+    pub fn set_public(&self, room_id: &RoomId, public: bool) -> Result<(), Error> {
+        if public {
+            self.publicroomids.insert(room_id.as_bytes(), &[])?;
+        } else {
+            self.publicroomids.remove(room_id.as_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Walks the room's PDUs backwards from (and including) `upto_count`, most
+    /// recent first. Used to resolve state as it was at a given point in the room's
+    /// history rather than the room's current state.
+    fn room_pdus_up_to<'a>(
+        &'a self,
+        room_id: &RoomId,
+        upto_count: PduCount,
+    ) -> impl Iterator<Item = Result<PduEvent, Error>> + 'a {
+        let mut prefix = room_id.as_bytes().to_vec();
+        prefix.push(0xff);
+
+        self.pduid_pdu
+            .scan_prefix(&prefix)
+            .rev()
+            .filter_map(move |entry| {
+                let (key, value) = match entry {
+                    Ok(kv) => kv,
+                    Err(e) => return Some(Err(Error::from(e))),
+                };
+
+                let count = PduCount::from_be_bytes(
+                    key[key.len() - 8..]
+                        .try_into()
+                        .expect("pdu_id always ends in an 8-byte count"),
+                );
+                if count > upto_count {
+                    return None;
+                }
+
+                Some(
+                    serde_json::from_slice::<PduEvent>(&value)
+                        .map_err(|_| Error::bad_database("Invalid PDU in database.")),
+                )
+            })
+    }
+
+    fn count_of(&self, event_id: &EventId) -> Result<Option<PduCount>, Error> {
+        self.eventid_pduid
+            .get(event_id.as_bytes())?
+            .map(|pdu_id| {
+                Ok(PduCount::from_be_bytes(
+                    pdu_id[pdu_id.len() - 8..]
+                        .try_into()
+                        .expect("pdu_id always ends in an 8-byte count"),
+                ))
+            })
+            .transpose()
+    }
+
+    /// Resolves the `m.room.history_visibility` that applied to the room at the
+    /// point `event_id` was sent (not necessarily the room's current visibility).
+    /// Shared by any endpoint that needs to authorize access to a historical event.
+    pub fn history_visibility_at_event(
+        &self,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<HistoryVisibility>, Error> {
+        let count = match self.count_of(event_id)? {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+
+        for pdu in self.room_pdus_up_to(room_id, count) {
+            let pdu = pdu?;
+            if pdu.event_type == EventType::RoomHistoryVisibility
+                && pdu.state_key.as_deref() == Some("")
+            {
+                let content: HistoryVisibilityEventContent =
+                    serde_json::from_value(pdu.content).map_err(|_| {
+                        Error::bad_database("Invalid m.room.history_visibility event in database.")
+                    })?;
+                return Ok(Some(content.history_visibility));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Resolves `user_id`'s membership state in `room_id` as it stood at the point
+    /// `event_id` was sent, by walking the room's `m.room.member` events for that
+    /// user backwards from the event in question.
+    pub fn membership_at_event(
+        &self,
+        user_id: &UserId,
+        room_id: &RoomId,
+        event_id: &EventId,
+    ) -> Result<Option<MembershipState>, Error> {
+        let count = match self.count_of(event_id)? {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+        let state_key = user_id.to_string();
+
+        for pdu in self.room_pdus_up_to(room_id, count) {
+            let pdu = pdu?;
+            if pdu.event_type == EventType::RoomMember && pdu.state_key.as_deref() == Some(state_key.as_str())
+            {
+                let content: MemberEventContent = serde_json::from_value(pdu.content)
+                    .map_err(|_| Error::bad_database("Invalid m.room.member event in database."))?;
+                return Ok(Some(content.membership));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether `user_id` has ever actually joined `room_id`, at any point in its
+    /// history, regardless of whether they are still joined. Used to authorize
+    /// `shared` history_visibility, which is granted to anyone who was once a
+    /// member, but not to someone who was only ever invited or banned.
+    pub fn was_ever_joined(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool, Error> {
+        let state_key = user_id.to_string();
+
+        for pdu in self.room_pdus_up_to(room_id, PduCount::MAX) {
+            let pdu = pdu?;
+            if pdu.event_type == EventType::RoomMember && pdu.state_key.as_deref() == Some(state_key.as_str())
+            {
+                let content: MemberEventContent = serde_json::from_value(pdu.content)
+                    .map_err(|_| Error::bad_database("Invalid m.room.member event in database."))?;
+                if content.membership == MembershipState::Join {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Hard-deletes every trace of `room_id`: its PDUs, current state, joined-member
+    /// markers, and directory listing. Only ever called as compensating cleanup for
+    /// a room creation that failed before the room could have been shared with
+    /// anyone else (no one but the creator ever joined, and no server federates a
+    /// room it has never seen a PDU for), so unlike redacting a room that is live,
+    /// there is no append-only DAG shared with peers to reconcile afterwards.
+    pub fn delete_room(&self, room_id: &RoomId) -> Result<(), Error> {
+        let mut pdu_prefix = room_id.as_bytes().to_vec();
+        pdu_prefix.push(0xff);
+
+        for entry in self.pduid_pdu.scan_prefix(&pdu_prefix) {
+            let (pdu_id, pdu_bytes) = entry?;
+
+            if let Ok(pdu) = serde_json::from_slice::<PduEvent>(&pdu_bytes) {
+                self.eventid_pduid.remove(pdu.event_id.as_bytes())?;
+            }
+
+            self.pduid_pdu.remove(pdu_id)?;
+        }
+
+        for entry in self.roomstateid_pdu.scan_prefix(&pdu_prefix) {
+            let (key, _) = entry?;
+            self.roomstateid_pdu.remove(key)?;
+        }
+
+        for entry in self.roomuserid_joined.scan_prefix(&pdu_prefix) {
+            let (key, _) = entry?;
+            self.roomuserid_joined.remove(key)?;
+        }
+
+        let room_id_bytes = room_id.as_bytes().to_vec();
+        for entry in self.alias_roomid.iter() {
+            let (alias, aliased_room_id) = entry?;
+            if aliased_room_id.as_ref() == room_id_bytes.as_slice() {
+                self.alias_roomid.remove(alias)?;
+            }
+        }
+
+        self.publicroomids.remove(&room_id_bytes)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_rooms() -> Rooms {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("failed to open in-memory sled database");
+
+        Rooms {
+            pduid_pdu: db.open_tree("pduid_pdu").unwrap(),
+            eventid_pduid: db.open_tree("eventid_pduid").unwrap(),
+            roomstateid_pdu: db.open_tree("roomstateid_pdu").unwrap(),
+            roomuserid_joined: db.open_tree("roomuserid_joined").unwrap(),
+            alias_roomid: db.open_tree("alias_roomid").unwrap(),
+            publicroomids: db.open_tree("publicroomids").unwrap(),
+            globalcount: db.open_tree("globalcount").unwrap(),
+        }
+    }
+
+    fn room_id() -> RoomId {
+        RoomId::try_from("!room:example.com").unwrap()
+    }
+
+    fn user_id() -> UserId {
+        UserId::try_from("@alice:example.com").unwrap()
+    }
+
+    /// Inserts a bare-bones `m.room.member` PDU at the next global count, bypassing
+    /// `append_pdu` (and the `Globals`/`AccountData` it needs) so these tests can
+    /// exercise the store in isolation. Returns the event id.
+    fn insert_member_pdu(
+        rooms: &Rooms,
+        room_id: &RoomId,
+        user_id: &UserId,
+        membership: MembershipState,
+    ) -> EventId {
+        let count = rooms.next_count().unwrap();
+        let event_id = EventId::try_from(format!("$event{}:example.com", count)).unwrap();
+        let state_key = user_id.to_string();
+
+        let pdu = PduEvent {
+            event_id: event_id.clone(),
+            room_id: room_id.clone(),
+            sender: user_id.clone(),
+            origin_server_ts: count,
+            event_type: EventType::RoomMember,
+            content: serde_json::to_value(MemberEventContent {
+                membership,
+                displayname: None,
+                avatar_url: None,
+                is_direct: None,
+                third_party_invite: None,
+            })
+            .unwrap(),
+            state_key: Some(state_key.clone()),
+            unsigned: None,
+            redacts: None,
+        };
+        let pdu_bytes = serde_json::to_vec(&pdu).unwrap();
+
+        let pdu_id = Rooms::pdu_id(room_id, count);
+        rooms.pduid_pdu.insert(&pdu_id, pdu_bytes.as_slice()).unwrap();
+        rooms
+            .eventid_pduid
+            .insert(event_id.as_bytes(), pdu_id)
+            .unwrap();
+        rooms
+            .roomstateid_pdu
+            .insert(
+                Rooms::state_key(room_id, &EventType::RoomMember, &state_key),
+                pdu_bytes.as_slice(),
+            )
+            .unwrap();
+
+        let joined_key = Rooms::joined_key(room_id, user_id);
+        if membership == MembershipState::Join {
+            rooms.roomuserid_joined.insert(joined_key, &[]).unwrap();
+        } else {
+            rooms.roomuserid_joined.remove(joined_key).unwrap();
+        }
+
+        event_id
+    }
+
+    #[test]
+    fn joined_visibility_survives_leaving_afterwards() {
+        let rooms = open_rooms();
+        let room_id = room_id();
+        let user_id = user_id();
+
+        insert_member_pdu(&rooms, &room_id, &user_id, MembershipState::Join);
+        // Stand-in for a message sent by `user_id` while they were joined.
+        let message_event = insert_member_pdu(&rooms, &room_id, &user_id, MembershipState::Join);
+        insert_member_pdu(&rooms, &room_id, &user_id, MembershipState::Leave);
+
+        assert_eq!(
+            rooms
+                .membership_at_event(&user_id, &room_id, &message_event)
+                .unwrap(),
+            Some(MembershipState::Join),
+            "membership at the event's own point in time must still read Join \
+             even though the user has since left"
+        );
+        assert!(!rooms.is_joined(&user_id, &room_id).unwrap());
+    }
+
+    #[test]
+    fn shared_visibility_denies_invite_only_and_banned_users() {
+        let rooms = open_rooms();
+        let room_id = room_id();
+
+        let invited_only = UserId::try_from("@bob:example.com").unwrap();
+        insert_member_pdu(&rooms, &room_id, &invited_only, MembershipState::Invite);
+        assert!(!rooms.was_ever_joined(&invited_only, &room_id).unwrap());
+
+        let banned = UserId::try_from("@carol:example.com").unwrap();
+        insert_member_pdu(&rooms, &room_id, &banned, MembershipState::Ban);
+        assert!(!rooms.was_ever_joined(&banned, &room_id).unwrap());
+    }
+
+    #[test]
+    fn shared_visibility_allows_member_who_joined_after_the_fact() {
+        let rooms = open_rooms();
+        let room_id = room_id();
+        let user_id = user_id();
+
+        // A message sent before `user_id` ever appears in the room.
+        let earlier_event = insert_member_pdu(
+            &rooms,
+            &room_id,
+            &UserId::try_from("@dave:example.com").unwrap(),
+            MembershipState::Join,
+        );
+        insert_member_pdu(&rooms, &room_id, &user_id, MembershipState::Join);
+
+        assert_eq!(
+            rooms
+                .membership_at_event(&user_id, &room_id, &earlier_event)
+                .unwrap(),
+            None,
+            "the user had no membership event yet at that point in time"
+        );
+        assert!(
+            rooms.was_ever_joined(&user_id, &room_id).unwrap(),
+            "shared visibility must still allow a member who joined after the \
+             fact to read history from before they joined"
+        );
+    }
+}